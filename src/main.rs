@@ -1,123 +1,776 @@
-mod controller {
+mod config {
     use lazy_static::lazy_static;
-    use rppal::pwm::{Channel, Polarity, Pwm};
+    use serde::Deserialize;
     use std::env;
+    use std::fs;
+
+    #[derive(Debug, Deserialize, Default)]
+    #[serde(default)]
+    pub struct Config {
+        pub adapter: String,
+        pub controller: ControllerConfig,
+        pub metrics: MetricsConfig,
+        pub sensors: SensorsConfig,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(default)]
+    pub struct ControllerConfig {
+        pub setpoint_f: f64,
+        pub min_duty_cycle: f64,
+        pub max_duty_cycle: f64,
+        pub pid_kp: f64,
+        pub pid_ki: f64,
+        pub pid_kd: f64,
+        pub fan_curve: Option<Vec<(f64, f64)>>,
+        pub fan_curve_deadband_f: f64,
+        pub pwm_channel: u8,
+        pub pwm_frequency_hz: f64,
+        // Many PWM fans stall out and stop spinning below this duty; the controller will never
+        // command less than this (aside from 0.0, i.e. fully off).
+        pub min_spin_duty_cycle: f64,
+    }
+
+    impl Default for ControllerConfig {
+        fn default() -> Self {
+            Self {
+                setpoint_f: 78.0,
+                min_duty_cycle: 0.65,
+                max_duty_cycle: 1.0,
+                pid_kp: 0.05,
+                pid_ki: 0.002,
+                pid_kd: 0.0,
+                fan_curve: None,
+                fan_curve_deadband_f: 0.5,
+                pwm_channel: 0,
+                pwm_frequency_hz: 25_000.0,
+                min_spin_duty_cycle: 0.2,
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, Default)]
+    #[serde(default)]
+    pub struct GrafanaConfig {
+        pub url: String,
+        pub username: String,
+        pub password: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(default)]
+    pub struct MqttConfig {
+        pub broker: String,
+        pub port: u16,
+        pub username: Option<String>,
+        pub password: Option<String>,
+        pub topic_prefix: String,
+    }
+
+    impl Default for MqttConfig {
+        fn default() -> Self {
+            Self {
+                broker: String::new(),
+                port: 1883,
+                username: None,
+                password: None,
+                topic_prefix: "iceman/kitchen/fan1".to_string(),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(default)]
+    pub struct MetricsConfig {
+        pub tach_gpio_pin: u8,
+        pub fan_pulses_per_revolution: f64,
+        pub grafana: Option<GrafanaConfig>,
+        pub mqtt: Option<MqttConfig>,
+    }
+
+    impl Default for MetricsConfig {
+        fn default() -> Self {
+            Self {
+                tach_gpio_pin: 17,
+                fan_pulses_per_revolution: 2.0,
+                grafana: None,
+                mqtt: None,
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(default)]
+    pub struct SensorsConfig {
+        pub avg_samples: usize,
+        pub ema_alpha: Option<f32>,
+    }
+
+    impl Default for SensorsConfig {
+        fn default() -> Self {
+            Self {
+                avg_samples: 5,
+                ema_alpha: None,
+            }
+        }
+    }
+
+    impl Config {
+        fn load() -> Self {
+            let path = env::var("ICEMAN_CONFIG").unwrap_or_else(|_| "/etc/iceman.toml".to_string());
+
+            let mut config = match fs::read_to_string(&path) {
+                Ok(raw) => {
+                    toml::from_str(&raw).unwrap_or_else(|e| panic!("Invalid config at {path}: {e}"))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+                Err(e) => panic!("Could not read config at {path}: {e}"),
+            };
+
+            config.apply_env_overrides();
+            config
+        }
+
+        fn apply_env_overrides(&mut self) {
+            self.adapter = env_override("ICEMAN_ADAPTER", self.adapter.clone());
+
+            self.controller.setpoint_f = env_override("ICEMAN_SETPOINT", self.controller.setpoint_f);
+            self.controller.min_duty_cycle =
+                env_override("ICEMAN_MIN_DUTY_CYCLE", self.controller.min_duty_cycle);
+            self.controller.max_duty_cycle =
+                env_override("ICEMAN_MAX_DUTY_CYCLE", self.controller.max_duty_cycle);
+            self.controller.pid_kp = env_override("ICEMAN_PID_KP", self.controller.pid_kp);
+            self.controller.pid_ki = env_override("ICEMAN_PID_KI", self.controller.pid_ki);
+            self.controller.pid_kd = env_override("ICEMAN_PID_KD", self.controller.pid_kd);
+            self.controller.fan_curve_deadband_f = env_override(
+                "ICEMAN_FAN_CURVE_DEADBAND",
+                self.controller.fan_curve_deadband_f,
+            );
+            self.controller.pwm_channel =
+                env_override("ICEMAN_PWM_CHANNEL", self.controller.pwm_channel);
+            self.controller.pwm_frequency_hz =
+                env_override("ICEMAN_PWM_FREQUENCY_HZ", self.controller.pwm_frequency_hz);
+            self.controller.min_spin_duty_cycle = env_override(
+                "ICEMAN_MIN_SPIN_DUTY_CYCLE",
+                self.controller.min_spin_duty_cycle,
+            );
+            if let Ok(raw) = env::var("ICEMAN_FAN_CURVE") {
+                self.controller.fan_curve = Some(parse_fan_curve(&raw));
+            }
+
+            self.sensors.avg_samples =
+                env_override("ICEMAN_SENSOR_AVG_SAMPLES", self.sensors.avg_samples);
+            if let Ok(raw) = env::var("ICEMAN_SENSOR_EMA_ALPHA") {
+                self.sensors.ema_alpha = Some(raw.parse().expect("variable is a valid f32"));
+            }
+
+            self.metrics.tach_gpio_pin =
+                env_override("ICEMAN_TACH_GPIO_PIN", self.metrics.tach_gpio_pin);
+            self.metrics.fan_pulses_per_revolution = env_override(
+                "ICEMAN_FAN_PULSES_PER_REV",
+                self.metrics.fan_pulses_per_revolution,
+            );
+
+            if let Ok(url) = env::var("GRAFANA_API_INFLUXDB_URL") {
+                self.metrics.grafana.get_or_insert_with(GrafanaConfig::default).url = url;
+            }
+            if let Some(grafana) = self.metrics.grafana.as_mut() {
+                if let Ok(username) = env::var("GRAFANA_API_USERNAME") {
+                    grafana.username = username;
+                }
+                if let Ok(password) = env::var("GRAFANA_API_PASSWORD") {
+                    grafana.password = password;
+                }
+            }
+
+            if let Ok(broker) = env::var("ICEMAN_MQTT_BROKER") {
+                self.metrics.mqtt.get_or_insert_with(MqttConfig::default).broker = broker;
+            }
+            if let Some(mqtt) = self.metrics.mqtt.as_mut() {
+                mqtt.port = env_override("ICEMAN_MQTT_PORT", mqtt.port);
+                if let Ok(username) = env::var("ICEMAN_MQTT_USERNAME") {
+                    mqtt.username = Some(username);
+                }
+                if let Ok(password) = env::var("ICEMAN_MQTT_PASSWORD") {
+                    mqtt.password = Some(password);
+                }
+                mqtt.topic_prefix =
+                    env_override("ICEMAN_MQTT_TOPIC_PREFIX", mqtt.topic_prefix.clone());
+            }
+        }
+    }
+
+    fn env_override<T>(key: &str, current: T) -> T
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match env::var(key) {
+            Ok(raw) => raw
+                .parse()
+                .unwrap_or_else(|e| panic!("{key} is invalid: {e}")),
+            Err(_) => current,
+        }
+    }
+
+    // e.g. ICEMAN_FAN_CURVE="60:0.0,70:0.5,80:1.0" - breakpoints are sorted by temp below.
+    pub fn parse_fan_curve(raw: &str) -> Vec<(f64, f64)> {
+        let mut points: Vec<(f64, f64)> = raw
+            .split(',')
+            .map(|point| {
+                let (temp, duty) = point
+                    .trim()
+                    .split_once(':')
+                    .unwrap_or_else(|| panic!("ICEMAN_FAN_CURVE point {point:?} must be temp:duty"));
+
+                let temp = temp
+                    .parse::<f64>()
+                    .unwrap_or_else(|e| panic!("ICEMAN_FAN_CURVE temp {temp:?} is invalid: {e}"));
+                let duty = duty
+                    .parse::<f64>()
+                    .unwrap_or_else(|e| panic!("ICEMAN_FAN_CURVE duty {duty:?} is invalid: {e}"));
+
+                (temp, duty)
+            })
+            .collect();
+
+        assert!(
+            !points.is_empty(),
+            "ICEMAN_FAN_CURVE must have at least one breakpoint"
+        );
+
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        points
+    }
+
+    lazy_static! {
+        pub static ref CONFIG: Config = Config::load();
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parse_fan_curve_sorts_breakpoints_by_temperature() {
+            let curve = parse_fan_curve("80:1.0,60:0.0,70:0.5");
+
+            assert_eq!(curve, vec![(60.0, 0.0), (70.0, 0.5), (80.0, 1.0)]);
+        }
+
+        #[test]
+        #[should_panic(expected = "must be temp:duty")]
+        fn parse_fan_curve_rejects_a_malformed_point() {
+            parse_fan_curve("60:0.0,bad");
+        }
+    }
+}
+
+mod controller {
+    use rppal::pwm::{Channel, Polarity, Pwm};
     use std::thread;
     use std::time::Duration;
-    use tracing::{debug, error, info};
+    use tracing::{debug, error, info, warn};
+
+    // Ticks run on a fixed sleep, so we can treat it as a constant dt for the PID terms.
+    const TICK_DT_SECS: f64 = 2.0;
+
+    /// A PWM-driven (or simulated) fan. Swappable so the control loop can run off a Pi.
+    pub trait FanActuator: Send {
+        fn set_duty_cycle(&self, duty: f64) -> Result<(), Box<dyn std::error::Error>>;
+    }
+
+    pub struct RppalPwmFan(Pwm);
+
+    impl FanActuator for RppalPwmFan {
+        fn set_duty_cycle(&self, duty: f64) -> Result<(), Box<dyn std::error::Error>> {
+            self.0.set_duty_cycle(duty)?;
+            Ok(())
+        }
+    }
+
+    /// Logs duty-cycle changes instead of driving real hardware, for `ICEMAN_ADAPTER=devmode`.
+    #[derive(Default)]
+    pub struct DevModeFan {
+        last_logged: std::sync::Mutex<Option<f64>>,
+    }
+
+    impl FanActuator for DevModeFan {
+        fn set_duty_cycle(&self, duty: f64) -> Result<(), Box<dyn std::error::Error>> {
+            let mut last_logged = self.last_logged.lock().unwrap();
+            if *last_logged != Some(duty) {
+                info!("[devmode] fan duty cycle -> {:.2}", duty);
+                *last_logged = Some(duty);
+            }
+
+            Ok(())
+        }
+    }
 
     pub fn run() -> Result<(), Box<dyn std::error::Error>> {
-        // Enable PWM channel 0 (BCM GPIO 18, physical pin 12) with the specified period
-        let pwm = Pwm::with_frequency(
-            Channel::Pwm0,
-            // 25 kHz
-            25_000.0,
-            // 100% duty cycle
-            1.0,
-            // Set pin high
-            Polarity::Normal,
-            // Enabled
-            true,
-        )?;
+        let config = &crate::config::CONFIG.controller;
+        validate_config(config);
+
+        let fan: Box<dyn FanActuator> = if crate::is_dev_mode() {
+            info!("ICEMAN_ADAPTER=devmode: using synthetic fan actuator");
+            Box::new(DevModeFan::default())
+        } else {
+            let channel = match config.pwm_channel {
+                0 => Channel::Pwm0,
+                1 => Channel::Pwm1,
+                other => panic!("Unsupported controller.pwm_channel {other}, expected 0 or 1"),
+            };
+
+            // Enable the configured PWM channel (channel 0 is BCM GPIO 18, physical pin 12).
+            let pwm = Pwm::with_frequency(
+                channel,
+                config.pwm_frequency_hz,
+                // 100% duty cycle
+                1.0,
+                // Set pin high
+                Polarity::Normal,
+                // Enabled
+                true,
+            )?;
+
+            info!({ hz = pwm.frequency().unwrap_or_default() }, "Initialized PWM");
+
+            Box::new(RppalPwmFan(pwm))
+        };
+
+        let mut sensor = crate::sensors::SensorFilter::new(
+            crate::sensors::probe_from_env(),
+            &crate::config::CONFIG.sensors,
+        );
 
         info!({
-            hz = pwm.frequency().unwrap_or_default(),
-            hot_temp = format!("{:.2}", *ICEMAN_HOT_TEMP),
-            max_duty_cycle = format!("{:.2}", *ICEMAN_MAX_DUTY_CYCLE),
-            min_duty_cycle = format!("{:.2}", *ICEMAN_MIN_DUTY_CYCLE),
-        }, "Initialized PWM");
+            mode = if config.fan_curve.is_some() { "curve" } else { "pid" },
+            setpoint = format!("{:.2}", config.setpoint_f),
+            max_duty_cycle = format!("{:.2}", config.max_duty_cycle),
+            min_duty_cycle = format!("{:.2}", config.min_duty_cycle),
+            kp = format!("{:.4}", config.pid_kp),
+            ki = format!("{:.4}", config.pid_ki),
+            kd = format!("{:.4}", config.pid_kd),
+        }, "Initialized fan controller");
 
         thread::spawn(move || {
-            // Init so the first tick resets to slow if needed.
-            let mut state: Option<FanState> = None;
+            // A configured fan curve takes over from the PID loop entirely.
+            let mut mode = match &config.fan_curve {
+                Some(curve) => ControlMode::Curve(curve.clone(), CurveState::default()),
+                None => ControlMode::Pid(PidState::default()),
+            };
 
             loop {
-                thread::sleep(Duration::from_secs(2));
-
-                match tick(&pwm, state.clone()) {
-                    Ok(new_state) => state = Some(new_state),
+                thread::sleep(Duration::from_secs_f64(TICK_DT_SECS));
 
-                    Err(err) => {
-                        error!("Error from controller tick: {:?}", err);
-                        continue;
-                    }
-                };
+                if let Err(err) = tick(fan.as_ref(), &mut sensor, &mut mode) {
+                    error!("Error from controller tick: {:?}", err);
+                }
             }
         });
 
         Ok(())
     }
 
-    #[derive(Debug, Clone)]
-    enum FanState {
-        Slow,
-        Fast,
+    // Catches config typos (a stray `1.5`, a min above max) before they reach rppal, rather than
+    // surfacing as a confusing error or silent misbehavior deep in the tick loop.
+    fn validate_config(config: &crate::config::ControllerConfig) {
+        assert!(
+            (0.0..=1.0).contains(&config.min_duty_cycle),
+            "controller.min_duty_cycle ({}) must be within [0.0, 1.0]",
+            config.min_duty_cycle
+        );
+        assert!(
+            (0.0..=1.0).contains(&config.max_duty_cycle),
+            "controller.max_duty_cycle ({}) must be within [0.0, 1.0]",
+            config.max_duty_cycle
+        );
+        assert!(
+            config.min_duty_cycle <= config.max_duty_cycle,
+            "controller.min_duty_cycle ({}) must be <= controller.max_duty_cycle ({})",
+            config.min_duty_cycle,
+            config.max_duty_cycle
+        );
+        assert!(
+            (0.0..=1.0).contains(&config.min_spin_duty_cycle),
+            "controller.min_spin_duty_cycle ({}) must be within [0.0, 1.0]",
+            config.min_spin_duty_cycle
+        );
+        assert!(
+            config.min_spin_duty_cycle <= config.max_duty_cycle,
+            "controller.min_spin_duty_cycle ({}) must be <= controller.max_duty_cycle ({})",
+            config.min_spin_duty_cycle,
+            config.max_duty_cycle
+        );
+        assert!(
+            config.fan_curve_deadband_f >= 0.0,
+            "controller.fan_curve_deadband_f ({}) must be >= 0.0",
+            config.fan_curve_deadband_f
+        );
+
+        if let Some(curve) = &config.fan_curve {
+            for &(temp, duty) in curve {
+                assert!(
+                    (0.0..=1.0).contains(&duty),
+                    "controller.fan_curve duty {duty} at {temp}F must be within [0.0, 1.0]"
+                );
+            }
+
+            // Two breakpoints at the same temperature would give that temperature two duties, so
+            // the curve is required to be strictly increasing in temperature.
+            for window in curve.windows(2) {
+                let (t0, t1) = (window[0].0, window[1].0);
+                assert!(
+                    t0 < t1,
+                    "controller.fan_curve breakpoints must have strictly increasing temps, found {t0}F then {t1}F"
+                );
+            }
+        }
     }
 
-    lazy_static! {
-        pub static ref ICEMAN_HOT_TEMP: f64 = env::var("ICEMAN_HOT_TEMP")
-            .unwrap_or_else(|_| "78.0".into())
-            .parse::<f64>()
-            .expect("variable is a valid f64");
-        pub static ref ICEMAN_MAX_DUTY_CYCLE: f64 = env::var("ICEMAN_MAX_DUTY_CYCLE")
-            .unwrap_or_else(|_| "1.0".into())
-            .parse::<f64>()
-            .expect("variable is a valid f64");
-        pub static ref ICEMAN_MIN_DUTY_CYCLE: f64 = env::var("ICEMAN_MIN_DUTY_CYCLE")
-            .unwrap_or_else(|_| "0.65".into())
-            .parse::<f64>()
-            .expect("variable is a valid f64");
-    }
-
-    fn tick(pwm: &Pwm, state: Option<FanState>) -> Result<FanState, Box<dyn std::error::Error>> {
-        let temp = match crate::sensors::read_probe_temp() {
+    // Many PWM fans stall below ~20% duty; never command a nonzero duty under the configured
+    // floor, so a PID/curve output that dips too low doesn't stop the fan outright.
+    fn enforce_stall_floor(config: &crate::config::ControllerConfig, duty: f64) -> f64 {
+        if duty > 0.0 && duty < config.min_spin_duty_cycle {
+            warn!({
+                requested = format!("{:.2}", duty),
+                floor = format!("{:.2}", config.min_spin_duty_cycle),
+            }, "Requested duty cycle would stall the fan; substituting safe floor");
+
+            return config.min_spin_duty_cycle;
+        }
+
+        duty
+    }
+
+    enum ControlMode {
+        Pid(PidState),
+        Curve(Vec<(f64, f64)>, CurveState),
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct PidState {
+        integral: f64,
+        prev_error: f64,
+        prev_temp: f64,
+        primed: bool,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct CurveState {
+        last_temp: Option<f64>,
+        last_duty: f64,
+    }
+
+    // Clamps below the first breakpoint, above the last, and linearly interpolates between.
+    fn curve_duty(curve: &[(f64, f64)], temp: f64) -> f64 {
+        let (first_temp, first_duty) = curve[0];
+        if temp <= first_temp {
+            return first_duty;
+        }
+
+        let (last_temp, last_duty) = curve[curve.len() - 1];
+        if temp >= last_temp {
+            return last_duty;
+        }
+
+        let (t0, d0) = curve
+            .iter()
+            .copied()
+            .take_while(|(t, _)| *t <= temp)
+            .last()
+            .unwrap_or((first_temp, first_duty));
+        let (t1, d1) = curve
+            .iter()
+            .copied()
+            .find(|(t, _)| *t > temp)
+            .unwrap_or((last_temp, last_duty));
+
+        d0 + (d1 - d0) * (temp - t0) / (t1 - t0)
+    }
+
+    fn tick(
+        fan: &dyn FanActuator,
+        sensor: &mut crate::sensors::SensorFilter,
+        mode: &mut ControlMode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let config = &crate::config::CONFIG.controller;
+
+        let temp = match sensor.read_temp_f(&crate::config::CONFIG.sensors) {
             Ok(temp) => temp as f64,
             Err(err) => {
                 error!("Controller: Could not read temp sensor: {:?}", err);
                 error!(
                     "Scaling fan to {:.2}% for safety.",
-                    (*ICEMAN_MAX_DUTY_CYCLE * 100.0)
+                    (config.max_duty_cycle * 100.0)
                 );
-                pwm.set_duty_cycle(*ICEMAN_MAX_DUTY_CYCLE)?;
+                fan.set_duty_cycle(config.max_duty_cycle)?;
 
-                return Ok(FanState::Fast);
+                return Ok(());
             }
         };
 
+        let duty = match mode {
+            ControlMode::Pid(state) => tick_pid(config, temp, state),
+            ControlMode::Curve(curve, state) => tick_curve(config, temp, curve, state),
+        };
+        let duty = enforce_stall_floor(config, duty);
+
+        fan.set_duty_cycle(duty)?;
+
+        Ok(())
+    }
+
+    fn tick_pid(config: &crate::config::ControllerConfig, temp: f64, state: &mut PidState) -> f64 {
+        if !state.primed {
+            // First tick: seed prev_temp so derivative-on-measurement doesn't see a fake jump.
+            state.prev_temp = temp;
+            state.primed = true;
+        }
+
+        // Cooling convention: hotter than setpoint => positive error => more fan.
+        let error = temp - config.setpoint_f;
+        let duty_span = config.max_duty_cycle - config.min_duty_cycle;
+
+        state.integral += error * TICK_DT_SECS;
+        if config.pid_ki != 0.0 {
+            // Anti-windup: clamp so the integral term alone can't exceed the duty span.
+            let max_integral = duty_span / config.pid_ki.abs();
+            state.integral = state.integral.clamp(-max_integral, max_integral);
+        }
+
+        // Derivative-on-measurement: tracks the probe, not the error, so a setpoint change
+        // doesn't inject a derivative kick.
+        let derivative = (temp - state.prev_temp) / TICK_DT_SECS;
+
+        let duty = config.min_duty_cycle
+            + config.pid_kp * error
+            + config.pid_ki * state.integral
+            + config.pid_kd * derivative;
+        let duty = duty.clamp(config.min_duty_cycle, config.max_duty_cycle);
+
         debug!({
-            state = format!("{:?}", state),
-            temp = temp,
+            temp = format!("{:.2}", temp),
+            error = format!("{:.2}", error),
+            prev_error = format!("{:.2}", state.prev_error),
+            integral = format!("{:.2}", state.integral),
+            derivative = format!("{:.2}", derivative),
+            duty = format!("{:.2}", duty),
         }, "Current tick observation");
 
-        let new_state = match state {
-            Some(FanState::Slow) | None if temp >= *ICEMAN_HOT_TEMP => {
-                info!("Increasing fan speed to max power.");
-                pwm.set_duty_cycle(*ICEMAN_MAX_DUTY_CYCLE)?;
+        state.prev_error = error;
+        state.prev_temp = temp;
+
+        duty
+    }
 
-                FanState::Fast
+    fn tick_curve(
+        config: &crate::config::ControllerConfig,
+        temp: f64,
+        curve: &[(f64, f64)],
+        state: &mut CurveState,
+    ) -> f64 {
+        // Deadband: ignore jitter too small to matter so the PWM doesn't thrash near a breakpoint.
+        if let Some(last_temp) = state.last_temp {
+            if (temp - last_temp).abs() < config.fan_curve_deadband_f {
+                return state.last_duty;
             }
-            // To avoid churning at the temp boundary we will chill things for a little longer.
-            Some(FanState::Fast) | None if temp < (*ICEMAN_HOT_TEMP - 1.0) => {
-                info!("Slowing fan to whisper setting.");
-                pwm.set_duty_cycle(*ICEMAN_MIN_DUTY_CYCLE)?;
+        }
+
+        let duty = curve_duty(curve, temp);
+
+        debug!({
+            temp = format!("{:.2}", temp),
+            duty = format!("{:.2}", duty),
+        }, "Current tick observation");
+
+        state.last_temp = Some(temp);
+        state.last_duty = duty;
 
-                FanState::Slow
+        duty
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::config::ControllerConfig;
+
+        fn test_config() -> ControllerConfig {
+            ControllerConfig {
+                setpoint_f: 80.0,
+                min_duty_cycle: 0.2,
+                max_duty_cycle: 1.0,
+                pid_kp: 0.1,
+                pid_ki: 0.0,
+                pid_kd: 0.0,
+                fan_curve: None,
+                fan_curve_deadband_f: 0.5,
+                pwm_channel: 0,
+                pwm_frequency_hz: 25_000.0,
+                min_spin_duty_cycle: 0.2,
             }
-            // If there is no state change required, skip...
-            Some(some_state) => some_state,
-            None => unreachable!("State will always be set in the first two conditionals"),
-        };
+        }
+
+        #[test]
+        fn tick_pid_increases_duty_as_temp_rises_above_setpoint() {
+            let config = test_config();
+            let mut state = PidState::default();
+
+            let duty_at_setpoint = tick_pid(&config, 80.0, &mut state);
+            let duty_above_setpoint = tick_pid(&config, 90.0, &mut state);
+
+            assert_eq!(duty_at_setpoint, config.min_duty_cycle);
+            assert!(duty_above_setpoint > duty_at_setpoint);
+        }
+
+        #[test]
+        fn tick_pid_clamps_to_max_duty_cycle() {
+            let config = test_config();
+            let mut state = PidState::default();
+
+            let duty = tick_pid(&config, 200.0, &mut state);
+
+            assert_eq!(duty, config.max_duty_cycle);
+        }
+
+        #[test]
+        fn tick_pid_derivative_rewards_a_rising_temperature() {
+            let mut config = test_config();
+            config.pid_kp = 0.0;
+            config.pid_kd = 0.05;
+            let mut state = PidState::default();
+
+            // Prime prev_temp, then compare holding steady vs. rising from the same state.
+            tick_pid(&config, 80.0, &mut state);
+            let duty_flat = tick_pid(&config, 80.0, &mut state.clone());
+            let duty_rising = tick_pid(&config, 85.0, &mut state);
+
+            assert!(duty_rising > duty_flat);
+        }
+
+        #[test]
+        fn curve_duty_clamps_below_and_above_breakpoints() {
+            let curve = vec![(60.0, 0.0), (70.0, 0.5), (80.0, 1.0)];
+
+            assert_eq!(curve_duty(&curve, 50.0), 0.0);
+            assert_eq!(curve_duty(&curve, 90.0), 1.0);
+        }
 
-        Ok(new_state)
+        #[test]
+        fn curve_duty_interpolates_between_breakpoints() {
+            let curve = vec![(60.0, 0.0), (70.0, 0.5), (80.0, 1.0)];
+
+            assert_eq!(curve_duty(&curve, 65.0), 0.25);
+            assert_eq!(curve_duty(&curve, 75.0), 0.75);
+        }
     }
 }
 
 mod sensors {
+    use std::collections::VecDeque;
     use std::fs;
     use std::io::{self, Read};
     use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
     use tracing::debug;
 
+    /// A source of probe temperature readings. Swappable so the control loop can run off a Pi.
+    pub trait TempSensor: Send {
+        fn read_temp_f(&self) -> io::Result<f32>;
+    }
+
+    // The DS18B20 reports this exact value before its first real conversion completes.
+    const DS18B20_POWER_ON_DEFAULT_F: f32 = 185.0;
+
+    /// Smooths raw probe readings over ticks: an EMA when `sensors.ema_alpha` is configured,
+    /// otherwise a rolling average over the last `sensors.avg_samples` samples. Owns the
+    /// underlying sensor so its state (ring buffer / EMA) persists across ticks. The config is
+    /// threaded in per call (like `controller::tick_pid`/`tick_curve`) rather than read from the
+    /// global `CONFIG` directly, so it can be unit-tested with arbitrary fixtures.
+    pub struct SensorFilter {
+        inner: Box<dyn TempSensor>,
+        samples: VecDeque<f32>,
+        ema: Option<f32>,
+    }
+
+    impl SensorFilter {
+        pub fn new(inner: Box<dyn TempSensor>, config: &crate::config::SensorsConfig) -> Self {
+            Self {
+                inner,
+                samples: VecDeque::with_capacity(config.avg_samples),
+                ema: None,
+            }
+        }
+
+        pub fn read_temp_f(&mut self, config: &crate::config::SensorsConfig) -> io::Result<f32> {
+            let sample = self.inner.read_temp_f()?;
+
+            if sample == DS18B20_POWER_ON_DEFAULT_F {
+                debug!("Discarding DS18B20 power-on default reading ({DS18B20_POWER_ON_DEFAULT_F}F)");
+                return self.last_smoothed().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "No valid temperature reading yet")
+                });
+            }
+
+            if let Some(alpha) = config.ema_alpha {
+                let ema = match self.ema {
+                    Some(prev) => alpha * sample + (1.0 - alpha) * prev,
+                    None => sample,
+                };
+                self.ema = Some(ema);
+
+                return Ok(ema);
+            }
+
+            if self.samples.len() >= config.avg_samples {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(sample);
+
+            Ok(self.samples.iter().sum::<f32>() / self.samples.len() as f32)
+        }
+
+        fn last_smoothed(&self) -> Option<f32> {
+            self.ema.or_else(|| {
+                (!self.samples.is_empty())
+                    .then(|| self.samples.iter().sum::<f32>() / self.samples.len() as f32)
+            })
+        }
+    }
+
+    pub struct OneWireProbe;
+
+    impl TempSensor for OneWireProbe {
+        fn read_temp_f(&self) -> io::Result<f32> {
+            read_probe_temp()
+        }
+    }
+
+    /// Returns a synthetic, oscillating temperature instead of reading the 1-wire bus, for
+    /// `ICEMAN_ADAPTER=devmode`.
+    #[derive(Default)]
+    pub struct DevModeProbe {
+        ticks: AtomicU64,
+    }
+
+    impl TempSensor for DevModeProbe {
+        fn read_temp_f(&self) -> io::Result<f32> {
+            let tick = self.ticks.fetch_add(1, Ordering::Relaxed) as f64;
+            // Oscillates between ~65F and ~85F so PID/curve tuning can be exercised off-Pi.
+            let temp_f = 75.0 + 10.0 * (tick * 0.05).sin();
+
+            Ok(temp_f as f32)
+        }
+    }
+
+    pub fn probe_from_env() -> Box<dyn TempSensor> {
+        if crate::is_dev_mode() {
+            Box::new(DevModeProbe::default())
+        } else {
+            Box::new(OneWireProbe)
+        }
+    }
+
     pub fn read_probe_temp() -> io::Result<f32> {
         let device_dir = Path::new("/sys/bus/w1/devices");
         let sensor_dir = fs::read_dir(device_dir)?
@@ -165,67 +818,168 @@ mod sensors {
 
         Ok(temp_f)
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::cell::Cell;
+
+        // Feeds back a scripted series of readings instead of touching the 1-wire bus.
+        struct ScriptedSensor {
+            readings: Vec<f32>,
+            next: Cell<usize>,
+        }
+
+        impl TempSensor for ScriptedSensor {
+            fn read_temp_f(&self) -> io::Result<f32> {
+                let i = self.next.get();
+                self.next.set(i + 1);
+                Ok(self.readings[i])
+            }
+        }
+
+        fn scripted(readings: Vec<f32>) -> Box<dyn TempSensor> {
+            Box::new(ScriptedSensor {
+                readings,
+                next: Cell::new(0),
+            })
+        }
+
+        fn avg_config(avg_samples: usize) -> crate::config::SensorsConfig {
+            crate::config::SensorsConfig {
+                avg_samples,
+                ema_alpha: None,
+            }
+        }
+
+        fn ema_config(alpha: f32) -> crate::config::SensorsConfig {
+            crate::config::SensorsConfig {
+                avg_samples: 5,
+                ema_alpha: Some(alpha),
+            }
+        }
+
+        #[test]
+        fn sensor_filter_rolling_average_evicts_oldest_sample() {
+            let config = avg_config(5);
+            let mut filter = scripted_filter(vec![70.0, 72.0, 74.0, 76.0, 78.0, 80.0, 82.0]);
+
+            let mut last = 0.0;
+            for _ in 0..7 {
+                last = filter.read_temp_f(&config).unwrap();
+            }
+
+            // Window has evicted the first two samples: avg(74, 76, 78, 80, 82) == 78.
+            assert_eq!(last, 78.0);
+        }
+
+        #[test]
+        fn sensor_filter_respects_a_non_default_window_size() {
+            let config = avg_config(3);
+            let mut filter = scripted_filter(vec![70.0, 80.0, 90.0, 100.0]);
+
+            for _ in 0..3 {
+                filter.read_temp_f(&config).unwrap();
+            }
+            let last = filter.read_temp_f(&config).unwrap();
+
+            // Window of 3 has evicted the first sample: avg(80, 90, 100) == 90.
+            assert_eq!(last, 90.0);
+        }
+
+        #[test]
+        fn sensor_filter_ema_weights_toward_the_latest_sample() {
+            let config = ema_config(0.5);
+            let mut filter = scripted_filter(vec![70.0, 80.0]);
+
+            let first = filter.read_temp_f(&config).unwrap();
+            let second = filter.read_temp_f(&config).unwrap();
+
+            assert_eq!(first, 70.0);
+            assert_eq!(second, 75.0);
+        }
+
+        #[test]
+        fn sensor_filter_discards_ds18b20_power_on_default() {
+            let config = avg_config(5);
+            let mut filter = scripted_filter(vec![70.0, DS18B20_POWER_ON_DEFAULT_F]);
+
+            let first = filter.read_temp_f(&config).unwrap();
+            let second = filter.read_temp_f(&config).unwrap();
+
+            assert_eq!(first, 70.0);
+            assert_eq!(second, 70.0);
+        }
+
+        fn scripted_filter(readings: Vec<f32>) -> SensorFilter {
+            SensorFilter::new(scripted(readings), &avg_config(5))
+        }
+    }
 }
 
 mod metrics {
-    use lazy_static::lazy_static;
-    use rppal::gpio::{Gpio, Level as PinLevel, Trigger};
+    use rppal::gpio::{Gpio, InputPin, Level as PinLevel, Trigger};
+    use rumqttc::{Client as MqttClient, MqttOptions, QoS};
     use std::collections::HashMap;
-    use std::env;
     use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
     use std::sync::Arc;
     use std::thread;
     use std::time::Duration;
     use tracing::{debug, error, info};
 
-    lazy_static! {
-        pub static ref GRAFANA_API_INFLUXDB_URL: String =
-            env::var("GRAFANA_API_INFLUXDB_URL").expect("GRAFANA_API_INFLUXDB_URL must be set");
-        pub static ref GRAFANA_API_USERNAME: String =
-            env::var("GRAFANA_API_USERNAME").expect("GRAFANA_API_USERNAME must be set");
-        pub static ref GRAFANA_API_PASSWORD: String =
-            env::var("GRAFANA_API_PASSWORD").expect("GRAFANA_API_PASSWORD must be set");
-    }
-
     pub fn run() -> Result<(), Box<dyn std::error::Error>> {
-        // Show the env variables for grafana in debug mode.
-        debug!({
-            influxdb_url = GRAFANA_API_INFLUXDB_URL.as_str(),
-            api_username = GRAFANA_API_USERNAME.as_str(),
-            api_password = GRAFANA_API_PASSWORD.as_str(),
-        }, "Grafana API credentials");
-
-        // Set up the tach pin and rpm counter.
-        let gpio = Gpio::new()?;
-        let mut pin = gpio.get(17)?.into_input_pullup();
-        let rpm_counter = Arc::new(RpmCounter::new());
-
-        pin.set_async_interrupt(Trigger::Both, {
-            let rpm_counter = rpm_counter.clone();
-            let mut prev_level: Option<PinLevel> = None;
-
-            // Attempt a hacky debounce since the interrupt of rppal does not currently handle
-            // this.
-            move |level| {
-                if Some(level) == prev_level && prev_level.is_some() {
-                    return;
+        let config = &crate::config::CONFIG.metrics;
+
+        if let Some(grafana) = &config.grafana {
+            debug!({
+                influxdb_url = grafana.url.as_str(),
+                api_username = grafana.username.as_str(),
+            }, "Grafana API credentials");
+        }
+
+        let mqtt_client = match &config.mqtt {
+            Some(mqtt) => Some(connect_mqtt(mqtt)?),
+            None => None,
+        };
+
+        let (rpm_source, pin) = if crate::is_dev_mode() {
+            info!("ICEMAN_ADAPTER=devmode: using synthetic RPM source");
+            (RpmSource::DevMode(AtomicU64::new(0)), None)
+        } else {
+            // Set up the tach pin and rpm counter.
+            let gpio = Gpio::new()?;
+            let mut pin = gpio.get(config.tach_gpio_pin)?.into_input_pullup();
+            let rpm_counter = Arc::new(RpmCounter::new());
+
+            pin.set_async_interrupt(Trigger::Both, {
+                let rpm_counter = rpm_counter.clone();
+                let mut prev_level: Option<PinLevel> = None;
+
+                // Attempt a hacky debounce since the interrupt of rppal does not currently handle
+                // this.
+                move |level| {
+                    if Some(level) == prev_level && prev_level.is_some() {
+                        return;
+                    }
+                    prev_level = Some(level);
+
+                    rpm_counter.on_tick(level);
                 }
-                prev_level = Some(level);
+            })?;
 
-                rpm_counter.on_tick(level);
-            }
-        })?;
+            (RpmSource::Hardware(rpm_counter), Some(pin))
+        };
 
         thread::spawn(move || {
             // Keep in scope to avoid Droping the interrupt on this pin that counts rpms.
+            // Devmode has no pin to hold onto, hence the Option.
             let _pin = pin;
 
             loop {
                 thread::sleep(Duration::from_secs(5));
-                rpm_counter.compute_rpm_speed();
 
-                let rpm_speed = rpm_counter.load_rpm_speed();
-                if let Err(err) = tick(rpm_speed) {
+                let rpm_speed = rpm_source.tick();
+                if let Err(err) = tick(rpm_speed, config, mqtt_client.as_ref()) {
                     error!("Error from within metrics loop: {:?}", err);
                 }
             }
@@ -234,26 +988,78 @@ mod metrics {
         Ok(())
     }
 
-    fn tick(rpm_speed: u32) -> Result<(), Box<dyn std::error::Error>> {
-        publish_metric(
-            "fan_controller_rpm",
-            rpm_speed as f32,
-            HashMap::from([("location", "kitchen"), ("fan", "fan1")]),
-        )?;
+    // Connects to the broker and spawns the event loop thread that drives the publishes.
+    fn connect_mqtt(
+        mqtt: &crate::config::MqttConfig,
+    ) -> Result<MqttClient, Box<dyn std::error::Error>> {
+        info!({
+            broker = mqtt.broker.as_str(),
+            port = mqtt.port,
+        }, "Connecting to MQTT broker");
+
+        let mut mqtt_options = MqttOptions::new("iceman", mqtt.broker.clone(), mqtt.port);
+        mqtt_options.set_keep_alive(Duration::from_secs(30));
+
+        if let (Some(username), Some(password)) = (mqtt.username.as_ref(), mqtt.password.as_ref())
+        {
+            mqtt_options.set_credentials(username, password);
+        }
+
+        let (client, mut connection) = MqttClient::new(mqtt_options, 10);
+
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                if let Err(err) = notification {
+                    error!("MQTT connection error: {:?}", err);
+                }
+            }
+        });
+
+        Ok(client)
+    }
+
+    fn tick(
+        rpm_speed: u32,
+        config: &crate::config::MetricsConfig,
+        mqtt_client: Option<&MqttClient>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mqtt_topic_prefix = config
+            .mqtt
+            .as_ref()
+            .map(|mqtt| mqtt.topic_prefix.as_str())
+            .unwrap_or_default();
+
+        if let Some(grafana) = &config.grafana {
+            publish_metric(
+                grafana,
+                "fan_controller_rpm",
+                rpm_speed as f32,
+                HashMap::from([("location", "kitchen"), ("fan", "fan1")]),
+            )?;
+        }
+        publish_mqtt_metric(mqtt_client, mqtt_topic_prefix, "rpm", rpm_speed as f32)?;
 
         let probe_temp = crate::sensors::read_probe_temp()?;
-        publish_metric(
-            "fan_controller_temp",
-            probe_temp,
-            HashMap::from([("location", "kitchen"), ("probe", "probe1")]),
-        )?;
+        if let Some(grafana) = &config.grafana {
+            publish_metric(
+                grafana,
+                "fan_controller_temp",
+                probe_temp,
+                HashMap::from([("location", "kitchen"), ("probe", "probe1")]),
+            )?;
+        }
+        publish_mqtt_metric(mqtt_client, mqtt_topic_prefix, "temp", probe_temp)?;
 
         let cpu_temp = crate::sensors::read_cpu_temp()?;
-        publish_metric(
-            "fan_controller_cpu_temp",
-            cpu_temp,
-            HashMap::from([("location", "kitchen"), ("probe", "cpu")]),
-        )?;
+        if let Some(grafana) = &config.grafana {
+            publish_metric(
+                grafana,
+                "fan_controller_cpu_temp",
+                cpu_temp,
+                HashMap::from([("location", "kitchen"), ("probe", "cpu")]),
+            )?;
+        }
+        publish_mqtt_metric(mqtt_client, mqtt_topic_prefix, "cpu_temp", cpu_temp)?;
 
         info!({
             rpm_speed = format!("{:.2}", rpm_speed),
@@ -264,7 +1070,32 @@ mod metrics {
         Ok(())
     }
 
+    // Publishes a single metric as a retained message to `<topic_prefix>/<name>`. A no-op when
+    // MQTT publishing isn't configured.
+    fn publish_mqtt_metric(
+        mqtt_client: Option<&MqttClient>,
+        topic_prefix: &str,
+        name: &str,
+        value: f32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(client) = mqtt_client else {
+            return Ok(());
+        };
+
+        let topic = format!("{topic_prefix}/{name}");
+
+        debug!({
+            topic = topic.as_str(),
+            value = value,
+        }, "Publishing MQTT metric");
+
+        client.publish(topic, QoS::AtLeastOnce, true, value.to_string())?;
+
+        Ok(())
+    }
+
     fn publish_metric(
+        grafana: &crate::config::GrafanaConfig,
         metric_name: &str,
         value: f32,
         attributes: HashMap<&str, &str>,
@@ -285,11 +1116,8 @@ mod metrics {
 
         let client = Client::new();
         let res = client
-            .post(GRAFANA_API_INFLUXDB_URL.as_str())
-            .basic_auth(
-                &GRAFANA_API_USERNAME.as_str(),
-                Some(GRAFANA_API_PASSWORD.as_str()),
-            )
+            .post(grafana.url.as_str())
+            .basic_auth(&grafana.username, Some(grafana.password.as_str()))
             .body(metric)
             .send()?;
 
@@ -304,15 +1132,35 @@ mod metrics {
         }
     }
 
+    // Swappable source of the periodic RPM reading: a real tach counter driven by GPIO
+    // interrupts, or a synthetic value for `ICEMAN_ADAPTER=devmode` so the daemon runs off-Pi.
+    enum RpmSource {
+        Hardware(Arc<RpmCounter>),
+        DevMode(AtomicU64),
+    }
+
+    impl RpmSource {
+        fn tick(&self) -> u32 {
+            match self {
+                RpmSource::Hardware(counter) => {
+                    counter.compute_rpm_speed();
+                    counter.load_rpm_speed()
+                }
+                RpmSource::DevMode(ticks) => {
+                    let tick = ticks.fetch_add(1, Ordering::Relaxed) as f64;
+                    // Oscillates so devmode dashboards/logs have something to look at.
+                    (1200.0 + 300.0 * (tick * 0.2).sin()) as u32
+                }
+            }
+        }
+    }
+
     struct RpmCounter {
         last_read_ms: AtomicU64,
         rpm: AtomicU32,
         pulses: AtomicU64,
     }
 
-    // Noctua fans pulse two times for each revolution.
-    const FAN_PULSE: f64 = 2.0;
-
     impl RpmCounter {
         fn new() -> Self {
             Self {
@@ -336,7 +1184,8 @@ mod metrics {
             let prev = self.last_read_ms.load(Ordering::Acquire);
             let dt_secs = (now - prev) as f64 / 1_000_000.0;
             let pulses = self.pulses.swap(0, Ordering::SeqCst);
-            let rpm = (((pulses as f64) / dt_secs) / FAN_PULSE) * 60.0;
+            let fan_pulses_per_revolution = crate::config::CONFIG.metrics.fan_pulses_per_revolution;
+            let rpm = (((pulses as f64) / dt_secs) / fan_pulses_per_revolution) * 60.0;
 
             debug!({
                 now = now,
@@ -371,6 +1220,12 @@ lazy_static! {
     pub static ref LOG_LEVEL: String = env::var("LOG_LEVEL").unwrap_or_else(|_| "INFO".to_string());
 }
 
+// Set `adapter = "devmode"` (or ICEMAN_ADAPTER=devmode) to run off synthetic sensor/fan
+// adapters instead of real hardware.
+pub fn is_dev_mode() -> bool {
+    config::CONFIG.adapter.eq_ignore_ascii_case("devmode")
+}
+
 fn log_level_from_env() -> Level {
     match LOG_LEVEL.as_str() {
         "DEBUG" => Level::DEBUG,